@@ -11,6 +11,7 @@
 
 use std::path::PathBuf;
 use std::fs;
+use std::io::Read;
 use std::str::FromStr;
 use std::collections::HashMap;
 use std::convert::From;
@@ -21,7 +22,7 @@ use rustc_serialize::Decodable;
 use syntax::codemap::Span;
 use syntax::ast::{Path, ExprLit, Lit_, TokenTree, TtToken};
 use syntax::parse::token;
-use syntax::ext::base::{ExtCtxt, MacResult, MacEager};
+use syntax::ext::base::{ExtCtxt, MacResult, MacEager, DummyResult};
 use syntax::ext::source_util::expand_file;
 
 // A struct matching the entries in entities.json.
@@ -32,8 +33,50 @@ struct CharRef {
     //characters: String,  // Present in the file but we don't need it
 }
 
-// Build the map from entity names (and their prefixes) to characters.
-fn build_map(js: Json) -> Option<HashMap<String, [u32; 2]>> {
+// Bits of the per-entry metadata stored alongside each entry's codepoints.
+// The tokenizer's named-character-reference state needs all three: whether
+// a key names a complete entity (as opposed to being a mere prefix created
+// while building the trie-like lookup table), whether that entity is one of
+// the legacy references that's valid without a trailing ';', and whether
+// the key is itself a strict prefix of some longer entity name (so the
+// tokenizer knows whether to keep consuming input looking for a longer
+// match).
+pub const IS_ENTITY: u8 = 0x1;
+pub const NO_SEMICOLON: u8 = 0x2;
+pub const IS_PREFIX: u8 = 0x4;
+
+// Decode a single entities.json entry: strip the leading '&' from the key
+// and turn its `CharRef` value into a fixed-size codepoint pair. Shared by
+// `build_map` and `build_rev_map` so the two tables can't drift out of sync.
+// Returns `None` (rather than panicking) if the entry doesn't look like a
+// `CharRef` — a JSON Pointer can land on a sub-object that isn't actually
+// an entities.json table, and that needs to surface as a diagnostic, not a
+// compiler abort.
+fn decode_entry(k: String, v: Json) -> Option<(String, [u32; 2])> {
+    if !k.starts_with('&') {
+        return None;
+    }
+
+    let mut decoder = json::Decoder::new(v);
+    let CharRef { codepoints }: CharRef = match Decodable::decode(&mut decoder) {
+        Ok(c) => c,
+        Err(_) => return None,
+    };
+
+    if codepoints.len() < 1 || codepoints.len() > 2 {
+        return None;
+    }
+    let mut codepoint_pair = [0, 0];
+    for (i,n) in codepoints.into_iter().enumerate() {
+        codepoint_pair[i] = n;
+    }
+
+    // Slice off the initial '&'
+    Some((k[1..].to_string(), codepoint_pair))
+}
+
+// Build the map from entity names (and their prefixes) to (characters, flags).
+fn build_map(js: Json) -> Option<HashMap<String, ([u32; 2], u8)>> {
     let mut map = HashMap::new();
     let json_map = match js {
         Json::Object(m) => m,
@@ -42,47 +85,88 @@ fn build_map(js: Json) -> Option<HashMap<String, [u32; 2]>> {
 
     // Add every named entity to the map.
     for (k,v) in json_map.into_iter() {
-        let mut decoder = json::Decoder::new(v);
-        let CharRef { codepoints }: CharRef
-            = Decodable::decode(&mut decoder).ok().expect("bad CharRef");
-
-        assert!((codepoints.len() >= 1) && (codepoints.len() <= 2));
-        let mut codepoint_pair = [0, 0];
-        for (i,n) in codepoints.into_iter().enumerate() {
-            codepoint_pair[i] = n;
-        }
+        let (name, codepoint_pair) = match decode_entry(k, v) {
+            Some(entry) => entry,
+            None => return None,
+        };
 
-        // Slice off the initial '&'
-        assert!(k.chars().next() == Some('&'));
-        map.insert(k[1..].to_string(), codepoint_pair);
+        let mut flags = IS_ENTITY;
+        if !name.ends_with(';') {
+            flags |= NO_SEMICOLON;
+        }
+        map.insert(name, (codepoint_pair, flags));
     }
 
-    // Add every missing prefix of those keys, mapping to NULL characters.
-    map.insert("".to_string(), [0, 0]);
+    // Add every missing prefix of those keys, mapping to NULL characters
+    // with no IS_ENTITY flag: these exist purely so the tokenizer can tell
+    // it's still walking towards a possible longer match.
+    map.insert("".to_string(), ([0, 0], 0));
     let keys: Vec<String> = map.keys().map(|k| k.to_string()).collect();
     for k in keys.into_iter() {
         for n in 1 .. k.len() {
             let pfx = k[..n].to_string();
             if !map.contains_key(&pfx) {
-                map.insert(pfx, [0, 0]);
+                map.insert(pfx, ([0, 0], 0));
             }
         }
     }
 
+    // Now that every key (entity or prefix) is present, mark the ones that
+    // are themselves a strict prefix of some other key.
+    let keys: Vec<String> = map.keys().map(|k| k.to_string()).collect();
+    for k in keys.iter() {
+        for n in 0 .. k.len() {
+            let pfx = &k[..n];
+            let &mut (_, ref mut flags) = map.get_mut(pfx).unwrap();
+            *flags |= IS_PREFIX;
+        }
+    }
+
     Some(map)
 }
 
-// Expand named_entities!("path/to/entities.json") into an invocation of phf_map!().
-pub fn expand(cx: &mut ExtCtxt, sp: Span, tt: &[TokenTree]) -> Box<MacResult+'static> {
-    let usage = "Usage: named_entities!(\"path/to/entities.json\")";
-
-    // Argument to the macro should be a single literal string: a path to
-    // entities.json, relative to the file containing the macro invocation.
-    let json_filename = match tt {
-        [TtToken(_, token::Literal(token::Lit::Str_(s), _))] => s.as_str().to_string(),
-        _ => ext_bail!(cx, sp, usage),
+// Build the reverse map from codepoint sequences to the canonical entity
+// name that should be used to re-encode them.  Where several names decode
+// to the same codepoints, prefer the shortest semicolon-terminated name, so
+// serializers produce stable, spec-compliant output rather than picking
+// whichever name the JSON map happened to iterate last.
+fn build_rev_map(js: Json) -> Option<HashMap<[u32; 2], String>> {
+    let mut map: HashMap<[u32; 2], String> = HashMap::new();
+    let json_map = match js {
+        Json::Object(m) => m,
+        _ => return None,
     };
 
+    for (k, v) in json_map.into_iter() {
+        let (name, codepoint_pair) = match decode_entry(k, v) {
+            Some(entry) => entry,
+            None => return None,
+        };
+
+        // Legacy (semicolon-less) references are never the canonical
+        // re-encoding; only consider names that end in ';'.
+        if !name.ends_with(';') {
+            continue;
+        }
+
+        let better = match map.get(&codepoint_pair) {
+            None => true,
+            Some(existing) => {
+                name.len() < existing.len() || (name.len() == existing.len() && name < *existing)
+            }
+        };
+        if better {
+            map.insert(codepoint_pair, name);
+        }
+    }
+
+    Some(map)
+}
+
+// Resolve `filename` the way `include!` resolves its argument: relative to
+// the directory owning the file that contains the macro invocation, rather
+// than the process's current directory.
+fn res_rel_file(cx: &mut ExtCtxt, sp: Span, filename: &str) -> PathBuf {
     // Get the result of calling file!() in the same place as our macro.
     let mod_filename = ext_expect!(cx, sp, match expand_file(cx, sp, &[]).make_expr() {
         Some(e) => match e.node {
@@ -95,25 +179,291 @@ pub fn expand(cx: &mut ExtCtxt, sp: Span, tt: &[TokenTree]) -> Box<MacResult+'st
         _ => None,
     }, "unexpected result from file!()");
 
-    // Combine those to get an absolute path to entities.json.
     let mut path: PathBuf = From::from(&mod_filename);
     path.pop();
-    path.push(&json_filename);
+    path.push(filename);
+    path
+}
+
+// Read and parse `path` as JSON, registering it with the codemap the same
+// way `include!` registers its argument, so that editing entities.json
+// invalidates this macro's expansion for incremental recompilation instead
+// of being invisible to the dependency tracker. Failures are reported as a
+// normal diagnostic at `sp` (the callers pass the span of the file-name
+// string literal), rather than a panic, matching how other syntax
+// extensions surface bad input.
+fn load_json(cx: &mut ExtCtxt, sp: Span, path: &PathBuf) -> Option<Json> {
+    let mut file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            cx.span_err(sp, &format!("couldn't open JSON file {}: {}", path.display(), e));
+            return None;
+        }
+    };
+
+    let mut src = String::new();
+    if let Err(e) = file.read_to_string(&mut src) {
+        cx.span_err(sp, &format!("couldn't read JSON file {}: {}", path.display(), e));
+        return None;
+    }
+
+    // `new_filemap_and_lines` is how `include!`/`include_str!` make the
+    // compiler aware of a file they read outside of the normal module
+    // loader, so it participates in dependency tracking.
+    cx.codemap().new_filemap_and_lines(&path.to_string_lossy(), &src);
+
+    match Json::from_str(&src) {
+        Ok(js) => Some(js),
+        Err(e) => {
+            cx.span_err(sp, &format!("couldn't parse JSON file {}: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+// Expand named_entities_rev!("path/to/entities.json") into an invocation of
+// phf_map!(), mapping codepoint sequences to the canonical entity name used
+// to re-encode them (e.g. by the XML/HTML serializer).
+pub fn expand_rev(cx: &mut ExtCtxt, sp: Span, tt: &[TokenTree]) -> Box<MacResult+'static> {
+    let usage = "Usage: named_entities_rev!(\"path/to/entities.json\")";
+
+    let (json_filename, file_sp) = match tt {
+        [TtToken(file_sp, token::Literal(token::Lit::Str_(s), _))] =>
+            (s.as_str().to_string(), file_sp),
+        _ => ext_bail!(cx, sp, usage),
+    };
+
+    let path = res_rel_file(cx, file_sp, &json_filename);
+    let js = match load_json(cx, file_sp, &path) {
+        Some(js) => js,
+        None => return DummyResult::expr(file_sp),
+    };
+    let map = match build_rev_map(js) {
+        Some(m) => m,
+        None => {
+            cx.span_err(file_sp, &format!(
+                "JSON file {} does not match entities.json format", path.display()));
+            return DummyResult::expr(file_sp);
+        }
+    };
+
+    // Emit a macro invocation of the form
+    //
+    //     phf_map!([c0, c1] => "name;", ...)
+    let toks: Vec<_> = map.into_iter().flat_map(|([c0, c1], name)| {
+        let name = &name[..];
+        (quote_tokens!(&mut *cx, [$c0, $c1] => $name,)).into_iter()
+    }).collect();
+    MacEager::expr(quote_expr!(&mut *cx, phf_map!($toks)))
+}
+
+// Resolve a JSON Pointer (RFC 6901) of the form "/foo/0/bar" against `js`,
+// walking `Json::Object` keys and `Json::Array` indices one token at a
+// time.  An empty pointer selects the whole document.
+fn resolve_pointer(js: Json, pointer: &str) -> Option<Json> {
+    if pointer.is_empty() {
+        return Some(js);
+    }
+    if !pointer.starts_with('/') {
+        return None;
+    }
+
+    let mut current = js;
+    for raw_tok in pointer[1..].split('/') {
+        // Per RFC 6901, unescape '~1' to '/' before '~0' to '~'.
+        let tok = raw_tok.replace("~1", "/").replace("~0", "~");
+        current = match current {
+            Json::Object(mut m) => match m.remove(&tok) {
+                Some(v) => v,
+                None => return None,
+            },
+            Json::Array(mut a) => match usize::from_str(&tok) {
+                Ok(i) if i < a.len() => a.swap_remove(i),
+                _ => return None,
+            },
+            _ => return None,
+        };
+    }
+    Some(current)
+}
 
-    // Open the JSON file, parse it, and build the map from names to characters.
-    let mut json_file = ext_expect!(cx, sp, fs::File::open(&path).ok(),
-        "can't open JSON file");
-    let js = ext_expect!(cx, sp, Json::from_reader(&mut json_file).ok(),
-        "can't parse JSON file");
-    let map = ext_expect!(cx, sp, build_map(js),
-        "JSON file does not match entities.json format");
+// Expand named_entities!("path/to/entities.json") or
+// named_entities!("path/to/entities.json", "/json/pointer") into an
+// invocation of phf_map!().  The optional second argument is a JSON Pointer
+// (RFC 6901) selecting the sub-object to build the map from, so the entity
+// table can live embedded inside a larger shared JSON document.
+pub fn expand(cx: &mut ExtCtxt, sp: Span, tt: &[TokenTree]) -> Box<MacResult+'static> {
+    let usage = "Usage: named_entities!(\"path/to/entities.json\"[, \"/json/pointer\"])";
+
+    // Arguments to the macro are a literal string path to entities.json,
+    // relative to the file containing the macro invocation, and an
+    // optional literal string JSON Pointer into that file.
+    let (json_filename, file_sp, pointer, pointer_sp) = match tt {
+        [TtToken(file_sp, token::Literal(token::Lit::Str_(s), _))] =>
+            (s.as_str().to_string(), file_sp, String::new(), file_sp),
+        [TtToken(file_sp, token::Literal(token::Lit::Str_(s), _)),
+         TtToken(_, token::Comma),
+         TtToken(pointer_sp, token::Literal(token::Lit::Str_(p), _))] =>
+            (s.as_str().to_string(), file_sp, p.as_str().to_string(), pointer_sp),
+        _ => ext_bail!(cx, sp, usage),
+    };
+
+    let path = res_rel_file(cx, file_sp, &json_filename);
+    let js = match load_json(cx, file_sp, &path) {
+        Some(js) => js,
+        None => return DummyResult::expr(file_sp),
+    };
+    let js = match resolve_pointer(js, &pointer) {
+        Some(js) => js,
+        None => {
+            cx.span_err(pointer_sp, &format!(
+                "JSON Pointer {:?} does not resolve to anything in {}",
+                pointer, path.display()));
+            return DummyResult::expr(pointer_sp);
+        }
+    };
+    let map = match build_map(js) {
+        Some(m) => m,
+        None => {
+            // Blame the pointer, not the file, when a pointer was given:
+            // it's the pointer's selection that produced a sub-object that
+            // doesn't look like an entities.json table.
+            if pointer.is_empty() {
+                cx.span_err(file_sp, &format!(
+                    "JSON file {} does not match entities.json format", path.display()));
+                return DummyResult::expr(file_sp);
+            } else {
+                cx.span_err(pointer_sp, &format!(
+                    "JSON Pointer {:?} in {} does not select an entities.json-shaped object",
+                    pointer, path.display()));
+                return DummyResult::expr(pointer_sp);
+            }
+        }
+    };
 
     // Emit a macro invocation of the form
     //
-    //     phf_map!(k => v, k => v, ...)
-    let toks: Vec<_> = map.into_iter().flat_map(|(k, [c0, c1])| {
+    //     phf_map!(k => ([c0, c1], flags), k => ([c0, c1], flags), ...)
+    let toks: Vec<_> = map.into_iter().flat_map(|(k, ([c0, c1], flags))| {
         let k = &k[..];
-        (quote_tokens!(&mut *cx, $k => [$c0, $c1],)).into_iter()
+        (quote_tokens!(&mut *cx, $k => ([$c0, $c1], $flags),)).into_iter()
     }).collect();
     MacEager::expr(quote_expr!(&mut *cx, phf_map!($toks)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_pointer, build_rev_map, build_map, IS_ENTITY, NO_SEMICOLON, IS_PREFIX};
+    use std::str::FromStr;
+    use rustc_serialize::json::Json;
+
+    fn doc() -> Json {
+        Json::from_str(r#"{
+            "a": {"b": 1, "c/d": 2, "e~f": 3},
+            "g": [10, 20, 30]
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn empty_pointer_selects_whole_document() {
+        assert_eq!(resolve_pointer(doc(), ""), Some(doc()));
+    }
+
+    #[test]
+    fn object_key_traversal() {
+        assert_eq!(resolve_pointer(doc(), "/a/b"), Some(Json::U64(1)));
+    }
+
+    #[test]
+    fn escaped_tilde_one_then_tilde_zero() {
+        // "~1" must unescape to '/' and "~0" to '~', so "c~1d" selects the
+        // key "c/d" and "e~0f" selects the key "e~f".
+        assert_eq!(resolve_pointer(doc(), "/a/c~1d"), Some(Json::U64(2)));
+        assert_eq!(resolve_pointer(doc(), "/a/e~0f"), Some(Json::U64(3)));
+    }
+
+    #[test]
+    fn array_index() {
+        assert_eq!(resolve_pointer(doc(), "/g/1"), Some(Json::U64(20)));
+    }
+
+    #[test]
+    fn array_index_out_of_bounds_is_none() {
+        assert_eq!(resolve_pointer(doc(), "/g/99"), None);
+    }
+
+    #[test]
+    fn array_non_numeric_token_is_none() {
+        assert_eq!(resolve_pointer(doc(), "/g/foo"), None);
+    }
+
+    #[test]
+    fn missing_object_key_is_none() {
+        assert_eq!(resolve_pointer(doc(), "/a/missing"), None);
+    }
+
+    #[test]
+    fn rev_map_prefers_shortest_semicolon_name() {
+        // "&amp;" and "&AMPersand;" (a fictitious longer alias) both decode
+        // to the same codepoint; the shorter name should win.
+        let js = Json::from_str(r#"{
+            "&amp;": {"codepoints": [38]},
+            "&AMPersand;": {"codepoints": [38]}
+        }"#).unwrap();
+        let map = build_rev_map(js).unwrap();
+        assert_eq!(map.get(&[38, 0]), Some(&"amp;".to_string()));
+    }
+
+    #[test]
+    fn rev_map_ignores_legacy_semicolon_less_names() {
+        // Only "&amp;" ends in ';'; the semicolon-less "&amp" alias must
+        // never be chosen as the canonical re-encoding.
+        let js = Json::from_str(r#"{
+            "&amp;": {"codepoints": [38]},
+            "&amp": {"codepoints": [38]}
+        }"#).unwrap();
+        let map = build_rev_map(js).unwrap();
+        assert_eq!(map.get(&[38, 0]), Some(&"amp;".to_string()));
+    }
+
+    #[test]
+    fn map_flags_legacy_entity_that_is_also_a_prefix() {
+        // "&ab" is a legacy (semicolon-less) entity, and its name "ab" is
+        // also a strict prefix of the longer entity "abcd;": it should
+        // carry all three flags.
+        let js = Json::from_str(r#"{
+            "&ab": {"codepoints": [1]},
+            "&abcd;": {"codepoints": [2]}
+        }"#).unwrap();
+        let map = build_map(js).unwrap();
+        let &(codepoints, flags) = map.get("ab").unwrap();
+        assert_eq!(codepoints, [1, 0]);
+        assert_eq!(flags, IS_ENTITY | NO_SEMICOLON | IS_PREFIX);
+    }
+
+    #[test]
+    fn map_flags_complete_entity_with_no_further_matches() {
+        let js = Json::from_str(r#"{
+            "&ab": {"codepoints": [1]},
+            "&abcd;": {"codepoints": [2]}
+        }"#).unwrap();
+        let map = build_map(js).unwrap();
+        let &(codepoints, flags) = map.get("abcd;").unwrap();
+        assert_eq!(codepoints, [2, 0]);
+        assert_eq!(flags, IS_ENTITY);
+    }
+
+    #[test]
+    fn map_flags_synthesized_prefix_is_not_an_entity() {
+        // "abc" is not itself a named entity, only an intermediate prefix
+        // generated while walking towards "abcd;".
+        let js = Json::from_str(r#"{
+            "&ab": {"codepoints": [1]},
+            "&abcd;": {"codepoints": [2]}
+        }"#).unwrap();
+        let map = build_map(js).unwrap();
+        let &(codepoints, flags) = map.get("abc").unwrap();
+        assert_eq!(codepoints, [0, 0]);
+        assert_eq!(flags, IS_PREFIX);
+    }
+}